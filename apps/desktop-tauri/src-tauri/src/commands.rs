@@ -1,34 +1,55 @@
 use base64::{engine::general_purpose, Engine as _};
 use tauri::{AppHandle, State};
 
-use crate::ws_server::WsServerState;
+use crate::ws_server::{PeerInfo, WsServerState};
 
 #[tauri::command]
 pub async fn start_ws_server(
     port: u16,
+    pairing_code: Option<String>,
     app: AppHandle,
     state: State<'_, WsServerState>,
 ) -> Result<String, String> {
-    state.start(port, app).await
+    state.start(port, pairing_code, app).await
 }
 
 #[tauri::command]
 pub async fn stop_ws_server(
+    grace_ms: Option<u64>,
+    app: AppHandle,
     state: State<'_, WsServerState>,
 ) -> Result<(), String> {
-    state.stop().await;
+    state.stop(grace_ms, &app).await;
     Ok(())
 }
 
 #[tauri::command]
 pub async fn ws_send(
     data: String,
+    peer_id: Option<String>,
     state: State<'_, WsServerState>,
 ) -> Result<(), String> {
     let bytes = general_purpose::STANDARD
         .decode(&data)
         .map_err(|e| format!("invalid base64: {}", e))?;
-    state.send(&bytes).await
+    state.send(peer_id.as_deref(), &bytes).await
+}
+
+#[tauri::command]
+pub async fn ws_send_stream(
+    path: String,
+    peer_id: Option<String>,
+    app: AppHandle,
+    state: State<'_, WsServerState>,
+) -> Result<(), String> {
+    state.send_stream(&path, peer_id.as_deref(), &app).await
+}
+
+#[tauri::command]
+pub async fn list_peers(
+    state: State<'_, WsServerState>,
+) -> Result<Vec<PeerInfo>, String> {
+    Ok(state.list_peers().await)
 }
 
 #[tauri::command]
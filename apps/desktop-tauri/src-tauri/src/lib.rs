@@ -1,4 +1,6 @@
 mod commands;
+mod pairing;
+mod transfer;
 mod ws_server;
 
 use ws_server::WsServerState;
@@ -10,6 +12,8 @@ pub fn run() {
             commands::start_ws_server,
             commands::stop_ws_server,
             commands::ws_send,
+            commands::ws_send_stream,
+            commands::list_peers,
             commands::get_local_ip,
         ])
         .run(tauri::generate_context!())
@@ -0,0 +1,30 @@
+//! Challenge-response pairing performed on every new connection when the
+//! server was started with a `pairing_code`, so a device can't exchange
+//! data with ShareGo just by reaching the bound port on a shared network.
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+/// length of the server-issued nonce, in bytes
+pub const NONCE_LEN: usize = 32;
+
+/// how long a client has to answer the challenge before the connection is dropped
+pub const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// generates a fresh random nonce for a single handshake attempt
+pub fn generate_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// verifies `candidate` is HMAC-SHA256(pairing_code, nonce); the comparison
+/// is constant-time, done inside `Mac::verify_slice`
+pub fn verify(pairing_code: &str, nonce: &[u8], candidate: &[u8]) -> bool {
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(pairing_code.as_bytes()) else {
+        return false;
+    };
+    mac.update(nonce);
+    mac.verify_slice(candidate).is_ok()
+}
@@ -0,0 +1,476 @@
+//! Chunked transfer protocol layered on top of WebSocket binary frames.
+//!
+//! Large files don't fit in a single `MAX_MESSAGE_SIZE` frame, so a transfer
+//! is split into a `START` frame announcing the total length, a run of
+//! `CHUNK` frames carrying the payload in order, and a final `END` frame.
+//! Either side may send `ABORT` to cancel a transfer in progress.
+//!
+//! Binary WebSocket frames share this single wire namespace with plain
+//! `ws_send` payloads, so every outgoing frame is tagged with a leading
+//! byte: [`MAGIC`] for a transfer-protocol frame, [`APP_PREFIX`] for an
+//! unframed application payload. Without this an arbitrary `ws_send`
+//! payload that happened to start with [`MAGIC`] would be silently
+//! misparsed as a protocol frame instead of delivered.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::ws_server::PeerId;
+
+/// leading byte of a chunked-transfer frame
+const MAGIC: u8 = 0xA5;
+
+/// leading byte of an unframed application payload sent via `ws_send`;
+/// stripped before the remainder is delivered as-is. Must never equal
+/// [`MAGIC`], or a plain payload could be mistaken for a transfer frame.
+pub const APP_PREFIX: u8 = 0x00;
+
+const TYPE_START: u8 = 0;
+const TYPE_CHUNK: u8 = 1;
+const TYPE_END: u8 = 2;
+const TYPE_ABORT: u8 = 3;
+
+const HEADER_PREFIX_LEN: usize = 1 /* magic */ + 1 /* type */ + 16 /* transfer id */;
+const START_HEADER_LEN: usize = HEADER_PREFIX_LEN + 8 /* total length */;
+const CHUNK_HEADER_LEN: usize = HEADER_PREFIX_LEN + 4 /* sequence number */;
+
+/// max size of a CHUNK frame's payload so the whole frame stays at or below
+/// `crate::ws_server::MAX_MESSAGE_SIZE`
+pub const CHUNK_PAYLOAD_MAX: usize = crate::ws_server::MAX_MESSAGE_SIZE - CHUNK_HEADER_LEN;
+
+/// transfers idle for longer than this are torn down so a peer that vanishes
+/// mid-transfer can't leak memory or temp files forever
+const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// transfers larger than this are buffered to a temp file instead of memory
+const LARGE_TRANSFER_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+enum Frame<'a> {
+    Start { id: Uuid, total_len: u64 },
+    Chunk { id: Uuid, seq: u32, payload: &'a [u8] },
+    End { id: Uuid },
+    Abort { id: Uuid },
+}
+
+/// parses a transfer-protocol frame; returns `None` if `data` doesn't start
+/// with [`MAGIC`] (callers check for [`APP_PREFIX`] before reaching here)
+fn try_decode(data: &[u8]) -> Option<Result<Frame<'_>, String>> {
+    if data.first() != Some(&MAGIC) {
+        return None;
+    }
+    if data.len() < HEADER_PREFIX_LEN {
+        return Some(Err("frame shorter than header".to_string()));
+    }
+
+    let frame_type = data[1];
+    let id = match Uuid::from_slice(&data[2..18]) {
+        Ok(id) => id,
+        Err(e) => return Some(Err(format!("invalid transfer id: {}", e))),
+    };
+
+    let result = match frame_type {
+        TYPE_START => {
+            if data.len() != START_HEADER_LEN {
+                Err("malformed START frame".to_string())
+            } else {
+                let total_len = u64::from_be_bytes(data[18..26].try_into().unwrap());
+                Ok(Frame::Start { id, total_len })
+            }
+        }
+        TYPE_CHUNK => {
+            if data.len() < CHUNK_HEADER_LEN {
+                Err("malformed CHUNK frame".to_string())
+            } else {
+                let seq = u32::from_be_bytes(data[18..22].try_into().unwrap());
+                Ok(Frame::Chunk { id, seq, payload: &data[CHUNK_HEADER_LEN..] })
+            }
+        }
+        TYPE_END => Ok(Frame::End { id }),
+        TYPE_ABORT => Ok(Frame::Abort { id }),
+        other => Err(format!("unknown frame type: {}", other)),
+    };
+
+    Some(result)
+}
+
+fn encode_start(id: Uuid, total_len: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(START_HEADER_LEN);
+    buf.push(MAGIC);
+    buf.push(TYPE_START);
+    buf.extend_from_slice(id.as_bytes());
+    buf.extend_from_slice(&total_len.to_be_bytes());
+    buf
+}
+
+fn encode_chunk(id: Uuid, seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(CHUNK_HEADER_LEN + payload.len());
+    buf.push(MAGIC);
+    buf.push(TYPE_CHUNK);
+    buf.extend_from_slice(id.as_bytes());
+    buf.extend_from_slice(&seq.to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn encode_end(id: Uuid) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_PREFIX_LEN);
+    buf.push(MAGIC);
+    buf.push(TYPE_END);
+    buf.extend_from_slice(id.as_bytes());
+    buf
+}
+
+fn encode_abort(id: Uuid) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_PREFIX_LEN);
+    buf.push(MAGIC);
+    buf.push(TYPE_ABORT);
+    buf.extend_from_slice(id.as_bytes());
+    buf
+}
+
+/// tags a plain `ws_send` payload with [`APP_PREFIX`] so the receiving end's
+/// `handle_frame` can tell it apart from a chunked-transfer frame
+pub fn wrap_app_message(data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + data.len());
+    buf.push(APP_PREFIX);
+    buf.extend_from_slice(data);
+    buf
+}
+
+enum Storage {
+    Memory(Vec<u8>),
+    File { file: File, path: PathBuf },
+}
+
+struct TransferState {
+    peer_id: PeerId,
+    total_len: u64,
+    next_seq: u32,
+    written: u64,
+    storage: Storage,
+    last_activity: Instant,
+}
+
+/// what the caller (the per-connection read loop) should do with an
+/// incoming WebSocket binary frame
+pub enum FrameOutcome {
+    /// an unframed `ws_send` payload; here it is with its tag stripped
+    Raw(Vec<u8>),
+    /// START/CHUNK accepted, nothing to surface yet
+    Accepted,
+    /// END matched the expected length; here's the reassembled file
+    Complete(Vec<u8>),
+    /// the frame violated the protocol (or the peer sent ABORT); send this
+    /// ABORT frame back to the sender (empty if nothing should be sent)
+    Reject(Vec<u8>),
+}
+
+/// tracks in-flight chunked transfers across all peers
+pub struct TransferRegistry {
+    transfers: Mutex<HashMap<Uuid, TransferState>>,
+}
+
+impl TransferRegistry {
+    pub fn new() -> Self {
+        Self { transfers: Mutex::new(HashMap::new()) }
+    }
+
+    /// handle a raw WebSocket binary frame received from `from_peer`
+    pub async fn handle_frame(&self, from_peer: &PeerId, data: &[u8]) -> FrameOutcome {
+        if data.first() == Some(&APP_PREFIX) {
+            return FrameOutcome::Raw(data[1..].to_vec());
+        }
+
+        let parsed = match try_decode(data) {
+            // neither APP_PREFIX nor MAGIC: not a frame we understand
+            None => return FrameOutcome::Reject(Vec::new()),
+            Some(Ok(frame)) => frame,
+            Some(Err(_)) => return FrameOutcome::Reject(Vec::new()),
+        };
+
+        match parsed {
+            Frame::Start { id, total_len } => {
+                let storage = if total_len > LARGE_TRANSFER_THRESHOLD {
+                    match Self::create_temp_file(id).await {
+                        Ok((file, path)) => Storage::File { file, path },
+                        Err(_) => return FrameOutcome::Reject(encode_abort(id)),
+                    }
+                } else {
+                    Storage::Memory(Vec::with_capacity(total_len as usize))
+                };
+
+                let old = self.transfers.lock().await.insert(
+                    id,
+                    TransferState {
+                        peer_id: from_peer.clone(),
+                        total_len,
+                        next_seq: 0,
+                        written: 0,
+                        storage,
+                        last_activity: Instant::now(),
+                    },
+                );
+                // a colliding transfer id is effectively unreachable (it's a
+                // fresh v4 uuid per transfer), but if it ever happens don't
+                // leak the replaced transfer's temp file
+                if let Some(old) = old {
+                    Self::discard(old.storage).await;
+                }
+                FrameOutcome::Accepted
+            }
+            Frame::Chunk { id, seq, payload } => {
+                let mut transfers = self.transfers.lock().await;
+                let Some(state) = transfers.get_mut(&id) else {
+                    return FrameOutcome::Reject(encode_abort(id));
+                };
+
+                if state.peer_id != *from_peer || seq != state.next_seq {
+                    if let Some(state) = transfers.remove(&id) {
+                        Self::discard(state.storage).await;
+                    }
+                    return FrameOutcome::Reject(encode_abort(id));
+                }
+
+                if let Err(_) = Self::append(&mut state.storage, payload).await {
+                    if let Some(state) = transfers.remove(&id) {
+                        Self::discard(state.storage).await;
+                    }
+                    return FrameOutcome::Reject(encode_abort(id));
+                }
+
+                state.next_seq += 1;
+                state.written += payload.len() as u64;
+                state.last_activity = Instant::now();
+                FrameOutcome::Accepted
+            }
+            Frame::End { id } => {
+                let Some(state) = self.transfers.lock().await.remove(&id) else {
+                    return FrameOutcome::Reject(encode_abort(id));
+                };
+
+                if state.peer_id != *from_peer || state.written != state.total_len {
+                    Self::discard(state.storage).await;
+                    return FrameOutcome::Reject(encode_abort(id));
+                }
+
+                match Self::finalize(state.storage).await {
+                    Ok(bytes) => FrameOutcome::Complete(bytes),
+                    Err(_) => FrameOutcome::Reject(encode_abort(id)),
+                }
+            }
+            Frame::Abort { id } => {
+                if let Some(state) = self.transfers.lock().await.remove(&id) {
+                    Self::discard(state.storage).await;
+                }
+                FrameOutcome::Reject(Vec::new())
+            }
+        }
+    }
+
+    /// drop any transfers owned by a peer that just disconnected
+    pub async fn cleanup_peer(&self, peer_id: &PeerId) {
+        let mut transfers = self.transfers.lock().await;
+        let dead: Vec<Uuid> = transfers
+            .iter()
+            .filter(|(_, s)| s.peer_id == *peer_id)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in dead {
+            if let Some(state) = transfers.remove(&id) {
+                Self::discard(state.storage).await;
+            }
+        }
+    }
+
+    /// true if there are no transfers in flight
+    pub async fn is_empty(&self) -> bool {
+        self.transfers.lock().await.is_empty()
+    }
+
+    /// tear down every in-flight transfer, discarding any temp files; used
+    /// when the server stops and the grace period for them to finish has run out
+    pub async fn clear_all(&self) {
+        for (_, state) in self.transfers.lock().await.drain() {
+            Self::discard(state.storage).await;
+        }
+    }
+
+    /// tear down transfers that have seen no traffic within `INACTIVITY_TIMEOUT`
+    pub async fn sweep_inactive(&self) {
+        let mut transfers = self.transfers.lock().await;
+        let dead: Vec<Uuid> = transfers
+            .iter()
+            .filter(|(_, s)| s.last_activity.elapsed() > INACTIVITY_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in dead {
+            if let Some(state) = transfers.remove(&id) {
+                Self::discard(state.storage).await;
+            }
+        }
+    }
+
+    async fn create_temp_file(id: Uuid) -> std::io::Result<(File, PathBuf)> {
+        let path = std::env::temp_dir().join(format!("sharego-transfer-{}.part", id));
+        let file = File::create(&path).await?;
+        Ok((file, path))
+    }
+
+    async fn append(storage: &mut Storage, payload: &[u8]) -> std::io::Result<()> {
+        match storage {
+            Storage::Memory(buf) => {
+                buf.extend_from_slice(payload);
+                Ok(())
+            }
+            Storage::File { file, .. } => file.write_all(payload).await,
+        }
+    }
+
+    async fn finalize(storage: Storage) -> std::io::Result<Vec<u8>> {
+        match storage {
+            Storage::Memory(buf) => Ok(buf),
+            Storage::File { mut file, path } => {
+                file.flush().await?;
+                let mut bytes = Vec::new();
+                let mut reassembled = File::open(&path).await?;
+                reassembled.read_to_end(&mut bytes).await?;
+                let _ = tokio::fs::remove_file(&path).await;
+                Ok(bytes)
+            }
+        }
+    }
+
+    async fn discard(storage: Storage) {
+        if let Storage::File { path, .. } = storage {
+            let _ = tokio::fs::remove_file(&path).await;
+        }
+    }
+}
+
+/// splits `path` into START/CHUNK/END frames for `ws_send_stream`, awaiting
+/// `on_frame` with each encoded frame and a `(sent, total)` progress pair,
+/// in order, so the caller can send the frame and report progress
+pub async fn stream_file<F, Fut>(path: &str, mut on_frame: F) -> Result<(), String>
+where
+    F: FnMut(Vec<u8>, u64, u64) -> Fut,
+    Fut: std::future::Future<Output = Result<(), String>>,
+{
+    let id = Uuid::new_v4();
+    let mut file = File::open(path)
+        .await
+        .map_err(|e| format!("failed to open {}: {}", path, e))?;
+    let total_len = file
+        .metadata()
+        .await
+        .map_err(|e| format!("failed to stat {}: {}", path, e))?
+        .len();
+
+    on_frame(encode_start(id, total_len), 0, total_len).await?;
+
+    let mut buf = vec![0u8; CHUNK_PAYLOAD_MAX];
+    let mut seq = 0u32;
+    let mut sent = 0u64;
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .map_err(|e| format!("failed to read {}: {}", path, e))?;
+        if n == 0 {
+            break;
+        }
+        sent += n as u64;
+        on_frame(encode_chunk(id, seq, &buf[..n]), sent, total_len).await?;
+        seq += 1;
+    }
+
+    on_frame(encode_end(id), sent, total_len).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str) -> PeerId {
+        id.to_string()
+    }
+
+    #[tokio::test]
+    async fn start_chunk_end_reassembles_the_file() {
+        let registry = TransferRegistry::new();
+        let id = Uuid::new_v4();
+        let data = b"hello chunked world";
+
+        assert!(matches!(
+            registry.handle_frame(&peer("a"), &encode_start(id, data.len() as u64)).await,
+            FrameOutcome::Accepted
+        ));
+        assert!(matches!(
+            registry.handle_frame(&peer("a"), &encode_chunk(id, 0, data)).await,
+            FrameOutcome::Accepted
+        ));
+
+        match registry.handle_frame(&peer("a"), &encode_end(id)).await {
+            FrameOutcome::Complete(bytes) => assert_eq!(bytes, data),
+            _ => panic!("expected the transfer to complete"),
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicate_sequence_is_rejected() {
+        let registry = TransferRegistry::new();
+        let id = Uuid::new_v4();
+        registry.handle_frame(&peer("a"), &encode_start(id, 8)).await;
+        registry.handle_frame(&peer("a"), &encode_chunk(id, 0, b"abcd")).await;
+
+        // seq 0 again instead of the expected seq 1
+        assert!(matches!(
+            registry.handle_frame(&peer("a"), &encode_chunk(id, 0, b"efgh")).await,
+            FrameOutcome::Reject(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn out_of_order_sequence_is_rejected() {
+        let registry = TransferRegistry::new();
+        let id = Uuid::new_v4();
+        registry.handle_frame(&peer("a"), &encode_start(id, 8)).await;
+
+        // jumps straight to seq 1, skipping the expected seq 0
+        assert!(matches!(
+            registry.handle_frame(&peer("a"), &encode_chunk(id, 1, b"abcd")).await,
+            FrameOutcome::Reject(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn chunk_from_a_different_peer_is_rejected() {
+        let registry = TransferRegistry::new();
+        let id = Uuid::new_v4();
+        registry.handle_frame(&peer("a"), &encode_start(id, 4)).await;
+
+        assert!(matches!(
+            registry.handle_frame(&peer("b"), &encode_chunk(id, 0, b"abcd")).await,
+            FrameOutcome::Reject(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn end_with_mismatched_total_length_is_rejected() {
+        let registry = TransferRegistry::new();
+        let id = Uuid::new_v4();
+        registry.handle_frame(&peer("a"), &encode_start(id, 100)).await;
+        registry.handle_frame(&peer("a"), &encode_chunk(id, 0, b"abcd")).await;
+
+        assert!(matches!(
+            registry.handle_frame(&peer("a"), &encode_end(id)).await,
+            FrameOutcome::Reject(_)
+        ));
+    }
+}
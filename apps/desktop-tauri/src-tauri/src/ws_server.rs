@@ -1,9 +1,10 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use base64::{engine::general_purpose, Engine as _};
-use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use futures_util::{SinkExt, StreamExt};
 use serde::Serialize;
 use socket2::{Domain, Protocol, Socket, Type};
 use tauri::{AppHandle, Emitter};
@@ -13,48 +14,123 @@ use tokio::{
     task::JoinHandle,
 };
 use tokio_tungstenite::{accept_async, tungstenite::Message};
+use uuid::Uuid;
+
+use crate::pairing;
+use crate::transfer::{self, FrameOutcome, TransferRegistry};
 
 /// max allowed message size in bytes (64 KB), matching the JS transport limit
-const MAX_MESSAGE_SIZE: usize = 65536;
+pub(crate) const MAX_MESSAGE_SIZE: usize = 65536;
+
+/// how often the background sweeper checks for stalled transfers
+const TRANSFER_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// how long the WS upgrade (`accept_async`) may take before the connection
+/// attempt is dropped; together with `pairing::HANDSHAKE_TIMEOUT` this bounds
+/// how long a single stalled connection can occupy its own accept task
+const WS_UPGRADE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how often each connection's heartbeat task sends a Ping
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// a peer with no traffic at all (including Pong replies) for this long is
+/// considered dead and torn down
+const PEER_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// how many outgoing frames a peer may have queued before `send` reports
+/// backpressure instead of waiting
+const SEND_QUEUE_CAPACITY: usize = 64;
+
+/// default grace period for `stop` to let queued sends and in-flight
+/// transfers finish before falling back to a hard close
+const DEFAULT_GRACE: Duration = Duration::from_secs(2);
+
+/// how often we poll for in-flight transfers to finish during the grace period
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
-type WsSink = SplitSink<
-    tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
-    Message,
->;
+type WsStream = tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>;
+
+/// unique id assigned to a peer for the lifetime of its connection
+pub type PeerId = String;
 
 #[derive(Serialize, Clone)]
 pub struct WsMessagePayload {
+    pub peer_id: PeerId,
     pub data: String,
 }
 
+#[derive(Serialize, Clone)]
+pub struct WsConnectionPayload {
+    pub peer_id: PeerId,
+    pub addr: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct WsClosePayload {
+    pub peer_id: PeerId,
+    pub addr: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    pub addr: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct WsSendProgressPayload {
+    pub sent: u64,
+    pub total: u64,
+}
+
+struct Peer {
+    /// the writer task drains this and is the sole owner of the connection's
+    /// write half, so a slow peer only ever backs up its own queue, never
+    /// the shared peers lock
+    tx: mpsc::Sender<Message>,
+    addr: SocketAddr,
+    /// updated on every inbound frame (including Pong); read by the
+    /// heartbeat task to detect a dead peer
+    last_seen: Arc<Mutex<Instant>>,
+    /// awaited (with a grace period) on stop so a slow drain isn't cut off;
+    /// aborted if that grace period runs out
+    writer_handle: JoinHandle<()>,
+}
+
 pub struct WsServerState {
-    pub sink: Arc<Mutex<Option<WsSink>>>,
+    pub peers: Arc<Mutex<HashMap<PeerId, Peer>>>,
     pub shutdown_tx: Arc<Mutex<Option<mpsc::Sender<()>>>>,
     pub listener_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
     /// serializes stop+start operations so they never interleave
     pub op_lock: Arc<Mutex<()>>,
+    transfers: Arc<TransferRegistry>,
+    sweeper_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
 impl WsServerState {
     pub fn new() -> Self {
         Self {
-            sink: Arc::new(Mutex::new(None)),
+            peers: Arc::new(Mutex::new(HashMap::new())),
             shutdown_tx: Arc::new(Mutex::new(None)),
             listener_handle: Arc::new(Mutex::new(None)),
             op_lock: Arc::new(Mutex::new(())),
+            transfers: Arc::new(TransferRegistry::new()),
+            sweeper_handle: Arc::new(Mutex::new(None)),
         }
     }
 
     pub async fn start(
         &self,
         port: u16,
+        pairing_code: Option<String>,
         app: AppHandle,
     ) -> Result<String, String> {
         // serialize against concurrent stop/start calls
         let _guard = self.op_lock.lock().await;
 
-        // stop any previous server and wait for the listener task to finish
-        self.stop_inner().await;
+        // stop any previous server and wait for the listener task to finish;
+        // no grace period needed here, we're about to replace it anyway
+        self.stop_inner(Duration::ZERO, &app).await;
 
         // bind with SO_REUSEADDR and retry to handle lingering sockets
         let listener = self.bind_with_retry(port, 5, 200).await?;
@@ -72,79 +148,43 @@ impl WsServerState {
         let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
         *self.shutdown_tx.lock().await = Some(shutdown_tx);
 
-        let sink_ref = self.sink.clone();
+        let peers_ref = self.peers.clone();
+        let transfers_ref = self.transfers.clone();
 
-        let handle = tokio::spawn(async move {
-            let has_peer = Arc::new(AtomicBool::new(false));
+        let sweeper_transfers = transfers_ref.clone();
+        let sweeper = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(TRANSFER_SWEEP_INTERVAL).await;
+                sweeper_transfers.sweep_inactive().await;
+            }
+        });
+        if let Some(old) = self.sweeper_handle.lock().await.replace(sweeper) {
+            old.abort();
+        }
 
+        let handle = tokio::spawn(async move {
             loop {
                 tokio::select! {
                     result = listener.accept() => {
                         match result {
-                            Ok((stream, _)) => {
-                                if has_peer.load(Ordering::SeqCst) {
-                                    drop(stream);
-                                    continue;
-                                }
-
-                                let ws = match accept_async(stream).await {
-                                    Ok(ws) => ws,
-                                    Err(_) => continue,
-                                };
-
-                                has_peer.store(true, Ordering::SeqCst);
-                                let (write, mut read) = ws.split();
-                                *sink_ref.lock().await = Some(write);
-
-                                let _ = app.emit("ws-connection", ());
-
-                                let app_read = app.clone();
-                                let sink_close = sink_ref.clone();
-                                let has_peer_clone = has_peer.clone();
-
+                            Ok((stream, remote_addr)) => {
+                                // spawned so one connection stuck mid-upgrade or
+                                // mid-handshake can never stall `listener.accept()`
+                                // for the rest of the LAN (see WS_UPGRADE_TIMEOUT)
+                                let peers_ref = peers_ref.clone();
+                                let transfers_ref = transfers_ref.clone();
+                                let pairing_code = pairing_code.clone();
+                                let app = app.clone();
                                 tokio::spawn(async move {
-                                    while let Some(msg) = read.next().await {
-                                        match msg {
-                                            Ok(Message::Binary(data)) => {
-                                                if data.len() > MAX_MESSAGE_SIZE {
-                                                    continue; // drop oversized messages
-                                                }
-                                                let encoded =
-                                                    general_purpose::STANDARD
-                                                        .encode(&data);
-                                                let _ = app_read.emit(
-                                                    "ws-message",
-                                                    WsMessagePayload {
-                                                        data: encoded,
-                                                    },
-                                                );
-                                            }
-                                            Ok(Message::Text(text)) => {
-                                                if text.len() > MAX_MESSAGE_SIZE {
-                                                    continue; // drop oversized messages
-                                                }
-                                                let encoded =
-                                                    general_purpose::STANDARD
-                                                        .encode(
-                                                            text.as_bytes(),
-                                                        );
-                                                let _ = app_read.emit(
-                                                    "ws-message",
-                                                    WsMessagePayload {
-                                                        data: encoded,
-                                                    },
-                                                );
-                                            }
-                                            Ok(Message::Close(_)) | Err(_) => {
-                                                break;
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-
-                                    has_peer_clone.store(false, Ordering::SeqCst);
-                                    *sink_close.lock().await = None;
-                                    let _ = app_read.emit("ws-close", ());
+                                    Self::accept_connection(
+                                        stream,
+                                        remote_addr,
+                                        pairing_code,
+                                        peers_ref,
+                                        transfers_ref,
+                                        app,
+                                    )
+                                    .await;
                                 });
                             }
                             Err(_) => break,
@@ -163,35 +203,364 @@ impl WsServerState {
         Ok(bound)
     }
 
-    pub async fn send(&self, data: &[u8]) -> Result<(), String> {
-        let mut sink_guard = self.sink.lock().await;
-        if let Some(sink) = sink_guard.as_mut() {
-            sink.send(Message::Binary(data.to_vec()))
-                .await
-                .map_err(|e| format!("send failed: {}", e))
+    /// queues `data` on a peer's writer task; returns a distinct
+    /// "backpressured" error instead of blocking when that peer's queue is full
+    ///
+    /// `data` is tagged with `transfer::APP_PREFIX` before it goes on the
+    /// wire, so an arbitrary payload that happens to start with the
+    /// chunked-transfer protocol's own magic byte isn't misparsed as a
+    /// transfer frame on the receiving end
+    pub async fn send(&self, peer_id: Option<&str>, data: &[u8]) -> Result<(), String> {
+        let message = Message::Binary(transfer::wrap_app_message(data));
+        let peers = self.peers.lock().await;
+
+        match peer_id {
+            Some(id) => {
+                let peer = peers.get(id).ok_or_else(|| format!("no such peer: {}", id))?;
+                Self::enqueue(peer, id, message)
+            }
+            None => {
+                if peers.is_empty() {
+                    return Err("no peer connected".to_string());
+                }
+
+                let errors: Vec<String> = peers
+                    .iter()
+                    .filter_map(|(id, peer)| Self::enqueue(peer, id, message.clone()).err())
+                    .collect();
+
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    Err(format!("send failed for some peers: {}", errors.join("; ")))
+                }
+            }
+        }
+    }
+
+    fn enqueue(peer: &Peer, id: &str, message: Message) -> Result<(), String> {
+        peer.tx.try_send(message).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => format!("peer backpressured: {}", id),
+            mpsc::error::TrySendError::Closed(_) => format!("peer disconnected: {}", id),
+        })
+    }
+
+    /// queues an already-encoded chunked-transfer frame (`frame` already
+    /// starts with `transfer::MAGIC`, not `APP_PREFIX` like `send`'s payload)
+    ///
+    /// unlike `send`/`enqueue`, this awaits the channel's own backpressure
+    /// instead of failing fast on a full queue: `send_stream` drives a whole
+    /// file through here one chunk at a time, and a momentarily full queue
+    /// is normal for a large transfer, not a reason to abort it. Only the
+    /// Sender is cloned out of `self.peers` before awaiting, so a slow peer
+    /// stalls just this transfer, not the shared peers lock
+    async fn send_transfer_frame(&self, peer_id: Option<&str>, frame: Vec<u8>) -> Result<(), String> {
+        let message = Message::Binary(frame);
+
+        let senders: Vec<(PeerId, mpsc::Sender<Message>)> = {
+            let peers = self.peers.lock().await;
+            match peer_id {
+                Some(id) => {
+                    let peer = peers.get(id).ok_or_else(|| format!("no such peer: {}", id))?;
+                    vec![(id.to_string(), peer.tx.clone())]
+                }
+                None => {
+                    if peers.is_empty() {
+                        return Err("no peer connected".to_string());
+                    }
+                    peers.iter().map(|(id, peer)| (id.clone(), peer.tx.clone())).collect()
+                }
+            }
+        };
+
+        let mut errors = Vec::new();
+        for (id, tx) in senders {
+            if tx.send(message.clone()).await.is_err() {
+                errors.push(format!("peer disconnected: {}", id));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
         } else {
-            Err("no peer connected".to_string())
+            Err(format!("send failed for some peers: {}", errors.join("; ")))
         }
     }
 
-    pub async fn stop(&self) {
+    /// splits the file at `path` into START/CHUNK/END frames and drives the
+    /// chunked transfer protocol, emitting `ws-send-progress` as it goes
+    pub async fn send_stream(
+        &self,
+        path: &str,
+        peer_id: Option<&str>,
+        app: &AppHandle,
+    ) -> Result<(), String> {
+        transfer::stream_file(path, |frame, sent, total| async move {
+            self.send_transfer_frame(peer_id, frame).await?;
+            let _ = app.emit("ws-send-progress", WsSendProgressPayload { sent, total });
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn list_peers(&self) -> Vec<PeerInfo> {
+        self.peers
+            .lock()
+            .await
+            .iter()
+            .map(|(peer_id, peer)| PeerInfo {
+                peer_id: peer_id.clone(),
+                addr: peer.addr.to_string(),
+            })
+            .collect()
+    }
+
+    /// stops accepting new connections, then gives existing peers up to
+    /// `grace_ms` (default `DEFAULT_GRACE`) to drain their outgoing queue and
+    /// finish any in-flight chunked-transfer reassembly before hard-closing
+    /// whatever's left
+    pub async fn stop(&self, grace_ms: Option<u64>, app: &AppHandle) {
         let _guard = self.op_lock.lock().await;
-        self.stop_inner().await;
+        let grace = grace_ms.map(Duration::from_millis).unwrap_or(DEFAULT_GRACE);
+        self.stop_inner(grace, app).await;
     }
 
     /// internal stop without acquiring op_lock (caller must hold it)
-    async fn stop_inner(&self) {
+    async fn stop_inner(&self, grace: Duration, app: &AppHandle) {
         if let Some(tx) = self.shutdown_tx.lock().await.take() {
             let _ = tx.send(()).await;
         }
-        if let Some(mut sink) = self.sink.lock().await.take() {
-            let _ = sink.close().await;
+        if let Some(handle) = self.sweeper_handle.lock().await.take() {
+            handle.abort();
         }
+
+        let deadline = tokio::time::Instant::now() + grace;
+
+        // dropping each peer's `tx` signals its writer task that no more
+        // sends are coming, so it drains whatever's still queued and closes.
+        // the peer is already gone from the map by the time the writer task
+        // would otherwise call `close_peer`, so emit `ws-close` here instead.
+        let mut writer_handles = Vec::new();
+        for (peer_id, peer) in self.peers.lock().await.drain() {
+            let Peer { tx, addr, writer_handle, .. } = peer;
+            drop(tx);
+            let _ = app.emit("ws-close", WsClosePayload { peer_id, addr: addr.to_string() });
+            writer_handles.push(writer_handle);
+        }
+
+        for mut handle in writer_handles {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if tokio::time::timeout(remaining, &mut handle).await.is_err() {
+                handle.abort(); // grace period elapsed; hard close
+            }
+        }
+
+        // give in-flight chunked-transfer reassembly the rest of the grace
+        // window, then drop whatever's left so we don't leak temp files
+        while !self.transfers.is_empty().await && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        self.transfers.clear_all().await;
+
         if let Some(handle) = self.listener_handle.lock().await.take() {
             let _ = handle.await;
         }
     }
 
+    /// removes a peer and emits `ws-close`, but only if it was still present
+    /// (the read loop and the heartbeat task can both race to tear down the
+    /// same peer; only the one that actually removes it should emit)
+    async fn close_peer(
+        peers: &Arc<Mutex<HashMap<PeerId, Peer>>>,
+        app: &AppHandle,
+        peer_id: &PeerId,
+    ) {
+        if let Some(peer) = peers.lock().await.remove(peer_id) {
+            let _ = app.emit(
+                "ws-close",
+                WsClosePayload { peer_id: peer_id.clone(), addr: peer.addr.to_string() },
+            );
+        }
+    }
+
+    /// completes the WS upgrade and (if `pairing_code` is set) the pairing
+    /// handshake for one freshly accepted TCP connection, then wires up its
+    /// writer, heartbeat and read-loop tasks; runs in its own spawned task so
+    /// a peer that stalls here never blocks the listener from accepting
+    /// anyone else
+    async fn accept_connection(
+        stream: tokio::net::TcpStream,
+        remote_addr: SocketAddr,
+        pairing_code: Option<String>,
+        peers_ref: Arc<Mutex<HashMap<PeerId, Peer>>>,
+        transfers_ref: Arc<TransferRegistry>,
+        app: AppHandle,
+    ) {
+        let Ok(Ok(mut ws)) = tokio::time::timeout(WS_UPGRADE_TIMEOUT, accept_async(stream)).await
+        else {
+            return;
+        };
+
+        if let Some(code) = &pairing_code {
+            if !Self::handshake(&mut ws, code).await {
+                let _ = ws.close(None).await;
+                return;
+            }
+        }
+
+        let peer_id = Uuid::new_v4().to_string();
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        let (mut write, mut read) = ws.split();
+
+        let (tx, rx) = mpsc::channel::<Message>(SEND_QUEUE_CAPACITY);
+
+        let writer_peers = peers_ref.clone();
+        let writer_app = app.clone();
+        let writer_peer_id = peer_id.clone();
+        let writer_handle = tokio::spawn(async move {
+            let mut rx = rx;
+            while let Some(msg) = rx.recv().await {
+                if write.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            let _ = write.close().await;
+            Self::close_peer(&writer_peers, &writer_app, &writer_peer_id).await;
+        });
+
+        peers_ref.lock().await.insert(
+            peer_id.clone(),
+            Peer { tx, addr: remote_addr, last_seen: last_seen.clone(), writer_handle },
+        );
+
+        let _ = app.emit(
+            "ws-connection",
+            WsConnectionPayload { peer_id: peer_id.clone(), addr: remote_addr.to_string() },
+        );
+
+        let app_heartbeat = app.clone();
+        let peers_heartbeat = peers_ref.clone();
+        let peer_id_heartbeat = peer_id.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(PING_INTERVAL);
+            ticker.tick().await; // first tick fires immediately
+
+            loop {
+                ticker.tick().await;
+
+                if last_seen.lock().await.elapsed() > PEER_TIMEOUT {
+                    Self::close_peer(&peers_heartbeat, &app_heartbeat, &peer_id_heartbeat).await;
+                    break;
+                }
+
+                let peers = peers_heartbeat.lock().await;
+                let Some(peer) = peers.get(&peer_id_heartbeat) else {
+                    break; // already torn down elsewhere
+                };
+                // a full queue just means the peer is backpressured,
+                // not dead; only a closed channel means the writer
+                // task gave up on this connection
+                let channel_closed = matches!(
+                    peer.tx.try_send(Message::Ping(Vec::new())),
+                    Err(mpsc::error::TrySendError::Closed(_))
+                );
+                drop(peers);
+
+                if channel_closed {
+                    Self::close_peer(&peers_heartbeat, &app_heartbeat, &peer_id_heartbeat).await;
+                    break;
+                }
+            }
+        });
+
+        let app_read = app.clone();
+        let peers_close = peers_ref.clone();
+        let transfers_read = transfers_ref.clone();
+        let peer_id_read = peer_id.clone();
+
+        tokio::spawn(async move {
+            while let Some(msg) = read.next().await {
+                if let Some(peer) = peers_close.lock().await.get(&peer_id_read) {
+                    *peer.last_seen.lock().await = Instant::now();
+                }
+
+                match msg {
+                    Ok(Message::Binary(data)) => {
+                        if data.len() > MAX_MESSAGE_SIZE {
+                            continue; // drop oversized messages
+                        }
+
+                        match transfers_read.handle_frame(&peer_id_read, &data).await {
+                            FrameOutcome::Raw(bytes) => {
+                                let encoded = general_purpose::STANDARD.encode(&bytes);
+                                let _ = app_read.emit(
+                                    "ws-message",
+                                    WsMessagePayload { peer_id: peer_id_read.clone(), data: encoded },
+                                );
+                            }
+                            FrameOutcome::Complete(bytes) => {
+                                let encoded = general_purpose::STANDARD.encode(&bytes);
+                                let _ = app_read.emit(
+                                    "ws-message",
+                                    WsMessagePayload { peer_id: peer_id_read.clone(), data: encoded },
+                                );
+                            }
+                            FrameOutcome::Reject(abort_frame) => {
+                                if !abort_frame.is_empty() {
+                                    if let Some(peer) = peers_close.lock().await.get(&peer_id_read) {
+                                        let _ = peer.tx.try_send(Message::Binary(abort_frame));
+                                    }
+                                }
+                            }
+                            FrameOutcome::Accepted => {}
+                        }
+                    }
+                    Ok(Message::Text(text)) => {
+                        if text.len() > MAX_MESSAGE_SIZE {
+                            continue; // drop oversized messages
+                        }
+                        let encoded = general_purpose::STANDARD.encode(text.as_bytes());
+                        let _ = app_read.emit(
+                            "ws-message",
+                            WsMessagePayload { peer_id: peer_id_read.clone(), data: encoded },
+                        );
+                    }
+                    Ok(Message::Ping(payload)) => {
+                        if let Some(peer) = peers_close.lock().await.get(&peer_id_read) {
+                            let _ = peer.tx.try_send(Message::Pong(payload));
+                        }
+                    }
+                    Ok(Message::Pong(_)) => {
+                        // last_seen was already refreshed above
+                    }
+                    Ok(Message::Close(_)) | Err(_) => {
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            transfers_read.cleanup_peer(&peer_id_read).await;
+            Self::close_peer(&peers_close, &app_read, &peer_id_read).await;
+        });
+    }
+
+    /// challenges a freshly accepted socket with a nonce and verifies the
+    /// HMAC-SHA256(pairing_code, nonce) response before it's trusted as a peer
+    async fn handshake(ws: &mut WsStream, pairing_code: &str) -> bool {
+        let nonce = pairing::generate_nonce();
+        if ws.send(Message::Binary(nonce.to_vec())).await.is_err() {
+            return false;
+        }
+
+        let response = tokio::time::timeout(pairing::HANDSHAKE_TIMEOUT, ws.next()).await;
+        let Ok(Some(Ok(Message::Binary(candidate)))) = response else {
+            return false;
+        };
+
+        pairing::verify(pairing_code, &nonce, &candidate)
+    }
+
     /// try binding with retries to handle the port not being released yet
     async fn bind_with_retry(
         &self,